@@ -1,9 +1,10 @@
 use error_iter::ErrorIter as _;
 use glam::f32::Vec2;
+use image::{Rgba, RgbaImage};
 use log::error;
 use pixels::{Error, Pixels, SurfaceTexture};
 use winit::dpi::LogicalSize;
-use winit::event::{Event, WindowEvent};
+use winit::event::{Event, MouseButton, WindowEvent};
 use winit::event_loop::EventLoop;
 use winit::keyboard::KeyCode;
 use winit::window::WindowBuilder;
@@ -18,6 +19,12 @@ const GRAV: f32 = 1.; // gravitation constant
 const THETA: f32 = 0.6; // Parameter affected both quality and speed. Too high, quality is low, too low fps is low
 const SOFTENING: f32 = 10.; // softening parameter based on wikipedia article on nbody
 const M: f32 = 1e4; // mass of central body
+const ROOT: usize = 0; // the quadtree root always lives at index 0 of the node arena
+const DENSITY_EXPORT_THRESHOLD: f32 = 50.; // node mass below which the density export stops subdividing
+// The quadtree covers the half-open range [0, WIDTH) x [0, HEIGHT); clamping a reflected
+// particle to exactly WIDTH/HEIGHT would place it outside every quadrant and drop it from
+// the tree, so pull it back inside the domain by this much instead.
+const EDGE_EPSILON: f32 = 1e-3;
 
 #[derive(Clone,Copy)]
 struct Particle {
@@ -42,11 +49,165 @@ impl Default for Particle{
     }
 }
 
+// How particles are treated when they cross the domain edge. Respawn is the original
+// behaviour (destroys and reseeds the particle, injecting/removing energy and angular
+// momentum); Reflect and Periodic return the particle to the domain with its speed unchanged
+// instead of reseeding it.
+#[derive(Clone, Copy, PartialEq)]
+enum BoundaryMode {
+    Respawn,
+    Reflect,
+    Periodic,
+}
+
+// The scenario used to seed `particles` at startup (or on a manual reseed). Each variant
+// generates its own `Vec<Particle>`, so the simulation can study collapse, collisions or
+// relaxation as well as the original single orbiting disk.
+#[derive(Clone, Copy, PartialEq)]
+enum InitialCondition {
+    RotatingDisk,
+    Lattice,
+    CollidingDisks,
+    ColdCloud,
+}
+
+impl InitialCondition {
+    // Parses a CLI arg such as `disk`, `lattice`, `collision` or `cold`
+    fn from_arg(s: &str) -> Option<Self> {
+        match s {
+            "disk" => Some(Self::RotatingDisk),
+            "lattice" => Some(Self::Lattice),
+            "collision" => Some(Self::CollidingDisks),
+            "cold" => Some(Self::ColdCloud),
+            _ => None,
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Self::RotatingDisk => Self::Lattice,
+            Self::Lattice => Self::CollidingDisks,
+            Self::CollidingDisks => Self::ColdCloud,
+            Self::ColdCloud => Self::RotatingDisk,
+        }
+    }
+
+    fn generate(self) -> Vec<Particle> {
+        match self {
+            Self::RotatingDisk => rotating_disk(),
+            Self::Lattice => lattice_bcc(),
+            Self::CollidingDisks => colliding_disks(),
+            Self::ColdCloud => cold_cloud(),
+        }
+    }
+}
+
+// A single central mass M orbited by N test particles on circular orbits (the original setup)
+fn rotating_disk() -> Vec<Particle> {
+    let mut rng = rand::thread_rng();
+    let uradius = Uniform::from(20.0..100.);
+    let utheta = Uniform::from(-std::f32::consts::PI..std::f32::consts::PI);
+    let mut particles = Vec::new();
+    particles.push(Particle{mass:M, r:Vec2{x: (WIDTH/2) as f32, y:(HEIGHT/2) as f32}, ..Default::default()});
+    // Make N random particles spread around center
+    for _ in 1..=N{
+        let r = uradius.sample(&mut rng);
+        let t = utheta.sample(&mut rng);
+        let v = (GRAV*M/r).sqrt();
+        particles.push(Particle {
+            mass: 1.,
+            r: Vec2 {
+                x: (WIDTH / 2) as f32 + r*t.cos(),
+                y: (HEIGHT / 2) as f32 + r*t.sin()
+            },
+            v: Vec2{
+                x: -v*t.sin(),
+                y: v*t.cos(),
+            },
+            // r is perpendicular to v
+            ..Default::default()
+        });
+    }
+    particles
+}
+
+// A body-centered fill of the domain: a square grid of spacing `spacing`, with alternate
+// rows offset by half a spacing so the points interleave like a BCC lattice's layers
+fn lattice_bcc() -> Vec<Particle> {
+    let box_size = WIDTH.min(HEIGHT) as f32;
+    let spacing = box_size / (N as f32).sqrt();
+    let n = (box_size / spacing + 0.5).floor() as i32;
+    let mut particles = Vec::new();
+    for iy in 0..n {
+        let offset = if iy % 2 == 0 { 0. } else { spacing / 2. };
+        for ix in 0..n {
+            let x = offset + ix as f32 * spacing;
+            let y = iy as f32 * spacing;
+            if x >= WIDTH as f32 || y >= HEIGHT as f32 {
+                continue;
+            }
+            particles.push(Particle { mass: 1., r: Vec2 { x, y }, ..Default::default() });
+        }
+    }
+    particles
+}
+
+// Two disks of particles, each orbiting its own bulk mass, approaching each other with
+// offset bulk velocities so they collide
+fn colliding_disks() -> Vec<Particle> {
+    let mut rng = rand::thread_rng();
+    let uradius = Uniform::from(10.0..80.);
+    let utheta = Uniform::from(-std::f32::consts::PI..std::f32::consts::PI);
+    let mut particles = Vec::new();
+    // Offset off the exact horizontal midline: it coincides with the quadtree's own split
+    // lines at every depth, and a bulk mass parked precisely on one is a recipe for edge-case
+    // routing bugs to cancel its self-gravity again the next time the tree layout changes.
+    let disks = [
+        (Vec2 { x: WIDTH as f32 * 0.3, y: HEIGHT as f32 * 0.5 + 1. }, Vec2 { x: 20., y: 0. }),
+        (Vec2 { x: WIDTH as f32 * 0.7, y: HEIGHT as f32 * 0.5 + 1. }, Vec2 { x: -20., y: 0. }),
+    ];
+    for (center, bulk_v) in disks {
+        particles.push(Particle { mass: M / 2., r: center, v: bulk_v, ..Default::default() });
+        for _ in 1..=N / 2 {
+            let r = uradius.sample(&mut rng);
+            let t = utheta.sample(&mut rng);
+            let v = (GRAV*(M/2.)/r).sqrt();
+            particles.push(Particle {
+                mass: 1.,
+                r: Vec2 { x: center.x + r*t.cos(), y: center.y + r*t.sin() },
+                // circular orbit around this disk's own bulk mass, plus the bulk velocity
+                // carrying the whole disk toward the other one
+                v: bulk_v + Vec2 { x: -v*t.sin(), y: v*t.cos() },
+                ..Default::default()
+            });
+        }
+    }
+    particles
+}
+
+// A uniform cloud of particles at rest, for studying gravitational collapse/relaxation
+fn cold_cloud() -> Vec<Particle> {
+    let mut rng = rand::thread_rng();
+    let ux = Uniform::from(0.0..WIDTH as f32);
+    let uy = Uniform::from(0.0..HEIGHT as f32);
+    let mut particles = Vec::new();
+    for _ in 0..N {
+        particles.push(Particle {
+            mass: 1.,
+            r: Vec2 { x: ux.sample(&mut rng), y: uy.sample(&mut rng) },
+            ..Default::default()
+        });
+    }
+    particles
+}
+
 // An enum for quad tree, either holds a final node with Some(index of particle) or None
-// or Branch, which is 4 quadrants within it
+// or Branch, holding the index into the node arena of the first of its four contiguous
+// children (children are always pushed together, so the other three are base+1..=base+3)
+#[derive(Clone, Copy)]
 enum QuadTree{
     Leaf(Option<usize>),
-    Branch([Box<QuadNode>; 4]),
+    Branch(u32),
 }
 
 // Metadata associated with Quadtree, such as position, width, height, mass contained within the
@@ -68,6 +229,20 @@ fn main() -> Result<(), Error> {
     let uradius = Uniform::from(20.0..100.);
     let utheta = Uniform::from(-std::f32::consts::PI..std::f32::consts::PI);
     let mut now = std::time::Instant::now();
+    let mut boundary_mode = BoundaryMode::Respawn;
+    // Index of the particle nearest the last click, highlighted in the render loop
+    let mut selected: Option<usize> = None;
+    // Barnes-Hut tuning parameters, now adjustable live instead of compile-time consts
+    let mut theta = THETA;
+    let mut softening = SOFTENING;
+    let mut grav = GRAV;
+    // Debug overlay: draws the quadtree's node boundaries, with a mass/area-shaded pixel per occupied leaf
+    let mut show_overlay = false;
+    // Scenario used to seed particles; pick via CLI arg (disk/lattice/collision/cold), defaulting
+    // to the original rotating disk, and cycle through the rest with a key at runtime
+    let mut initial_condition = std::env::args().nth(1)
+        .and_then(|s| InitialCondition::from_arg(&s))
+        .unwrap_or(InitialCondition::RotatingDisk);
     let window = {
         let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
         WindowBuilder::new()
@@ -79,27 +254,11 @@ fn main() -> Result<(), Error> {
     };
     // Boilerplate for pixels and winit setup
 
-    let mut particles : Vec<Particle> = Vec::new();
-    particles.push(Particle{mass:M, r:Vec2{x: (WIDTH/2) as f32, y:(HEIGHT/2) as f32}, ..Default::default()});
-    // Make N random particles spread around center
-    for _ in 1..=N{
-        let r = uradius.sample(&mut rng);
-        let t = utheta.sample(&mut rng);
-        let v = (GRAV*M/r).sqrt();
-        particles.push(Particle {
-            mass: 1.,
-            r: Vec2 {
-                x: (WIDTH / 2) as f32 + r*t.cos(),
-                y: (HEIGHT / 2) as f32 + r*t.sin()
-            },
-            v: Vec2{
-                x: -v*t.sin(),
-                y: v*t.cos(),
-            },
-            // r is perpendicular to v
-            ..Default::default()
-        });
-    }
+    // Node arena for the quadtree: cleared (not dropped) every frame so its capacity is
+    // reused instead of paying a Box alloc/free per node on every split.
+    let mut arena: Vec<QuadNode> = Vec::new();
+
+    let mut particles : Vec<Particle> = initial_condition.generate();
 
 
     // Used for deltatime later on
@@ -120,22 +279,31 @@ fn main() -> Result<(), Error> {
         {
             let frame = pixels.frame_mut();
             frame.fill(0);
-            for p in &particles {
+            for (i, p) in particles.iter().enumerate() {
                 let x = p.r.x as u32;
                 let y = p.r.y as u32;
                 // only render if within screen
                 if 0 < x && x < WIDTH && 0 < y && y < HEIGHT {
-                    let i = (WIDTH * y + x) as usize;
+                    let idx = (WIDTH * y + x) as usize;
                     // transition from blue to red based on magnitude of velocity
                     let d:f32 = match p.v.length(){
                         0.0..1. => 1.,
                         e => 1./e,
                     };
-                    let rgba = [(255.*(1.-d)) as u8 , 40, (255.*(d)) as u8, 0xff];
-                    frame[4 * i..(4 * i) + 4].copy_from_slice(&rgba);
+                    // the selected particle (from an AABB pick query) is highlighted in white
+                    let rgba = if Some(i) == selected {
+                        [0xff, 0xff, 0xff, 0xff]
+                    } else {
+                        [(255.*(1.-d)) as u8 , 40, (255.*(d)) as u8, 0xff]
+                    };
+                    frame[4 * idx..(4 * idx) + 4].copy_from_slice(&rgba);
                 }
             }
 
+            if show_overlay {
+                draw_quadtree_overlay(frame, &arena, ROOT);
+            }
+
             if let Err(err) = pixels.render() {
                 log_error("pixels.render", err);
                 elwt.exit();
@@ -156,34 +324,106 @@ fn main() -> Result<(), Error> {
                     return;
                 }
             }
+            // Cycle the boundary condition: Respawn -> Reflect -> Periodic -> Respawn
+            if input.key_pressed(KeyCode::KeyB) {
+                boundary_mode = match boundary_mode {
+                    BoundaryMode::Respawn => BoundaryMode::Reflect,
+                    BoundaryMode::Reflect => BoundaryMode::Periodic,
+                    BoundaryMode::Periodic => BoundaryMode::Respawn,
+                };
+            }
+            // Tune the Barnes-Hut quality/speed tradeoff live
+            if input.key_pressed(KeyCode::Equal) { theta += 0.05; }
+            if input.key_pressed(KeyCode::Minus) { theta = (theta - 0.05).max(0.); }
+            if input.key_pressed(KeyCode::BracketRight) { softening += 1.; }
+            if input.key_pressed(KeyCode::BracketLeft) { softening = (softening - 1.).max(0.); }
+            if input.key_pressed(KeyCode::Period) { grav += 0.1; }
+            if input.key_pressed(KeyCode::Comma) { grav = (grav - 0.1).max(0.); }
+            // Toggle the quadtree debug overlay
+            if input.key_pressed(KeyCode::KeyO) { show_overlay = !show_overlay; }
+            // Cycle the initial-condition scenario and reseed the particles from it
+            if input.key_pressed(KeyCode::KeyR) {
+                initial_condition = initial_condition.next();
+                particles = initial_condition.generate();
+                selected = None;
+            }
 
-            // Initialise the root node
-            let mut root = QuadNode {
+            // Reset the arena in place and push a fresh root; capacity from last frame is kept
+            arena.clear();
+            arena.push(QuadNode {
                 top_left: Vec2::ZERO,
                 width: WIDTH as f32,
                 height: HEIGHT as f32,
                 center_of_mass_sum: Vec2::ZERO,
                 mass: 0.,
                 qt: QuadTree::Leaf(None),
-            };
+            });
 
             // build tree
             for i in 0..particles.len(){
-                // If particle is not within the screen, respawm it as a new particle somewhere inside
-                if !(0. < particles[i].r.x && particles[i].r.x < WIDTH as f32 && 0. < particles[i].r.y && particles[i].r.y < HEIGHT as f32){
-                    let r = uradius.sample(&mut rng);
-                    let t = utheta.sample(&mut rng);
-                    particles[i].r = Vec2 {x: (WIDTH / 2) as f32 + r*t.cos(), y: (HEIGHT/2) as f32 + r*t.sin()};
-                    particles[i].v = Vec2{x: -r/100.*t.sin(), y: r/100.*t.cos()};
-                    particles[i].field = Vec2::ZERO;
-                    particles[i].first_iter = true;
+                match boundary_mode {
+                    // If particle is not within the screen, respawn it as a new particle somewhere inside
+                    BoundaryMode::Respawn => {
+                        if !(0. < particles[i].r.x && particles[i].r.x < WIDTH as f32 && 0. < particles[i].r.y && particles[i].r.y < HEIGHT as f32){
+                            let r = uradius.sample(&mut rng);
+                            let t = utheta.sample(&mut rng);
+                            particles[i].r = Vec2 {x: (WIDTH / 2) as f32 + r*t.cos(), y: (HEIGHT/2) as f32 + r*t.sin()};
+                            particles[i].v = Vec2{x: -r/100.*t.sin(), y: r/100.*t.cos()};
+                            particles[i].field = Vec2::ZERO;
+                            particles[i].first_iter = true;
+                        }
+                    },
+                    // Clamp to the wall and bounce: negate the velocity component that carried
+                    // the particle past the boundary
+                    BoundaryMode::Reflect => {
+                        if particles[i].r.x < 0. { particles[i].r.x = 0.; particles[i].v.x = -particles[i].v.x; }
+                        else if particles[i].r.x >= WIDTH as f32 { particles[i].r.x = WIDTH as f32 - EDGE_EPSILON; particles[i].v.x = -particles[i].v.x; }
+                        if particles[i].r.y < 0. { particles[i].r.y = 0.; particles[i].v.y = -particles[i].v.y; }
+                        else if particles[i].r.y >= HEIGHT as f32 { particles[i].r.y = HEIGHT as f32 - EDGE_EPSILON; particles[i].v.y = -particles[i].v.y; }
+                    },
+                    // Wrap around to the opposite edge so the domain is toroidal
+                    BoundaryMode::Periodic => {
+                        particles[i].r = wrap_periodic(particles[i].r);
+                    },
                 }
                 // Try adding the particle to root
-                put(&mut root, i, &particles);
+                put(&mut arena, ROOT, i, &particles);
+            }
+
+            // On click, query a small box around the cursor and select the nearest particle.
+            // input.cursor() is in window physical-pixel space, so map it through pixels'
+            // scaling/offset to get sim-space (512x512) coordinates before querying the tree.
+            if input.mouse_pressed(MouseButton::Left) {
+                if let Some(cursor_px) = input.cursor() {
+                    if let Ok((px, py)) = pixels.window_pos_to_pixel(cursor_px) {
+                        let cursor = Vec2 {x: px as f32, y: py as f32};
+                        let half = Vec2::splat(5.);
+                        let mut candidates: Vec<usize> = Vec::new();
+                        query(&arena, ROOT, (cursor - half, cursor + half), &mut candidates);
+                        selected = candidates.into_iter().min_by(|&a, &b| {
+                            let da = (particles[a].r - cursor).length_squared();
+                            let db = (particles[b].r - cursor).length_squared();
+                            da.partial_cmp(&db).unwrap()
+                        });
+                        if let Some(s) = selected {
+                            println!("selected particle {s} at {:?}, mass {}", particles[s].r, particles[s].mass);
+                        }
+                    }
+                }
+            }
+
+            // Dump a variance-adaptive density heatmap of the current tree to a PNG
+            if input.key_pressed(KeyCode::KeyP) {
+                let mut img = RgbaImage::new(WIDTH, HEIGHT);
+                render_density_image(&arena, ROOT, &mut img, DENSITY_EXPORT_THRESHOLD);
+                match img.save("density.png") {
+                    Ok(()) => println!("wrote density.png"),
+                    Err(err) => error!("density.png export failed: {err}"),
+                }
             }
 
             for i in 0..particles.len(){
-                calculate_field(&root, i, &mut particles);
+                calculate_field(&arena, ROOT, i, &mut particles, theta, softening, grav);
             }
             for i in 0..particles.len(){
                 // using particles[i].v = particles[i].field*dt returns an error, telling to use a
@@ -213,6 +453,14 @@ fn main() -> Result<(), Error> {
     res.map_err(|e| Error::UserDefined(Box::new(e)))
 }
 
+// Wrap a position into the half-open [0, WIDTH) x [0, HEIGHT) domain, used by Periodic
+// boundary mode so a particle leaving one edge re-enters on the opposite one
+fn wrap_periodic(r: Vec2) -> Vec2 {
+    // f32 rem_euclid can round a tiny negative up to exactly `size`, which is outside [0, size)
+    let wrap = |v: f32, size: f32| { let w = v.rem_euclid(size); if w < size { w } else { 0. } };
+    Vec2 { x: wrap(r.x, WIDTH as f32), y: wrap(r.y, HEIGHT as f32) }
+}
+
 fn log_error<E: std::error::Error + 'static>(method_name: &str, err: E) {
     error!("{method_name}() failed: {err}");
     for source in err.sources().skip(1) {
@@ -220,73 +468,351 @@ fn log_error<E: std::error::Error + 'static>(method_name: &str, err: E) {
     }
 }
 
-fn put(node: &mut QuadNode, i: usize, particles: &Vec<Particle>) {
+// Push the four children of `idx` (sized to its quadrants) onto the end of the arena and
+// return the base index. Reads `idx`'s own fields first, then pushes, then hands back a
+// plain index, so there's never a `&mut` into the arena alive across a `push` that might
+// reallocate it.
+fn split(arena: &mut Vec<QuadNode>, idx: usize) -> u32 {
+    let top_left = arena[idx].top_left;
+    let width = arena[idx].width;
+    let height = arena[idx].height;
+    let base = arena.len() as u32;
+    arena.push(QuadNode {top_left: Vec2 {x: top_left.x + width / 2., y: top_left.y                },width: width / 2.,height: height / 2.,center_of_mass_sum:Vec2::ZERO, mass: 0.,qt: QuadTree::Leaf(None)});
+    arena.push(QuadNode {top_left: Vec2 {x: top_left.x,               y: top_left.y                },width: width / 2.,height: height / 2.,center_of_mass_sum:Vec2::ZERO,mass: 0.,qt: QuadTree::Leaf(None)});
+    arena.push(QuadNode {top_left: Vec2 {x: top_left.x,               y: top_left.y + height / 2. },width: width / 2.,height: height / 2.,center_of_mass_sum:Vec2::ZERO,mass: 0.,qt: QuadTree::Leaf(None)});
+    arena.push(QuadNode {top_left: Vec2 {x: top_left.x + width / 2., y: top_left.y + height / 2. },width: width / 2.,center_of_mass_sum:Vec2::ZERO,height: height / 2.,mass: 0.,qt: QuadTree::Leaf(None)});
+    base
+}
+
+// Index of the child quadrant of `base` that contains `r`. Quadrants are half-open
+// [top_left, top_left + size) on both axes, so a point exactly on a split line belongs to
+// the quadrant below/right of it instead of falling between all four and being dropped from
+// the tree (this used to return None for any particle sitting exactly on a boundary, e.g.
+// the default scenario's central mass at (WIDTH/2, HEIGHT/2)). Only a point outside the
+// node's own box entirely (which callers never pass) would still miss every child.
+fn route_to_child(arena: &[QuadNode], base: usize, r: Vec2) -> Option<usize> {
+    (0..4).find(|&k| {
+        let c = &arena[base + k];
+        c.top_left.x <= r.x && r.x < c.top_left.x + c.width && c.top_left.y <= r.y && r.y < c.top_left.y + c.height
+    }).map(|k| base + k)
+}
+
+fn put(arena: &mut Vec<QuadNode>, idx: usize, i: usize, particles: &[Particle]) {
     let p = particles[i];
-    match &mut node.qt {
+    match arena[idx].qt {
         QuadTree::Leaf(particle) => {
-            node.center_of_mass_sum += p.mass*p.r;
-            node.mass += p.mass;
-            match particle.take() {
+            arena[idx].center_of_mass_sum += p.mass*p.r;
+            arena[idx].mass += p.mass;
+            match particle {
                 // If the leaf is empty, simply add particle
                 None => {
-                    node.qt = QuadTree::Leaf(Some(i));
+                    arena[idx].qt = QuadTree::Leaf(Some(i));
                 },
-                // If the leaf is occupied, split it into quadrants and to individual quad
-                // The quadtree associated with the node becomes a branch
+                // If the leaf is occupied, split it into quadrants and push both particles
+                // straight into their child quadrant (not back through `idx`, whose mass
+                // already reflects both from the accumulation above)
                 Some(u) => {
-                    node.qt = QuadTree::Branch([
-                        Box::new(QuadNode {top_left: Vec2 {x: node.top_left.x + node.width / 2., y: node.top_left.y                    },width: node.width / 2.,height: node.height / 2.,center_of_mass_sum:Vec2::ZERO, mass: 0.,qt: QuadTree::Leaf(None)}),
-                        Box::new(QuadNode {top_left: Vec2 {x: node.top_left.x,                   y: node.top_left.y                    },width: node.width / 2.,height: node.height / 2.,center_of_mass_sum:Vec2::ZERO,mass: 0.,qt: QuadTree::Leaf(None)}),
-                        Box::new(QuadNode {top_left: Vec2 {x: node.top_left.x,                   y: node.top_left.y + node.height / 2. },width: node.width / 2.,height: node.height / 2.,center_of_mass_sum:Vec2::ZERO,mass: 0.,qt: QuadTree::Leaf(None),}),
-                        Box::new(QuadNode {top_left: Vec2 {x: node.top_left.x + node.width / 2., y: node.top_left.y + node.height / 2. },width: node.width / 2.,center_of_mass_sum:Vec2::ZERO,height: node.height / 2.,mass: 0.,qt: QuadTree::Leaf(None)}),
-                    ]);
-                    put(node, u, particles);
-                    put(node, i, particles);
+                    let base = split(arena, idx);
+                    arena[idx].qt = QuadTree::Branch(base);
+                    if let Some(c) = route_to_child(arena, base as usize, particles[u].r) {
+                        put(arena, c, u, particles);
+                    }
+                    if let Some(c) = route_to_child(arena, base as usize, p.r) {
+                        put(arena, c, i, particles);
+                    }
                 }
             }
         },
-        // check which leaf in branch has the coordinates required to fit in the particle, and try
-        // putting in it
-        QuadTree::Branch(branch) => {
-            if      branch[0].top_left.x < p.r.x && p.r.x < branch[0].top_left.x + branch[0].width && branch[0].top_left.y < p.r.y && p.r.y < branch[0].top_left.y + branch[0].height  {put(&mut branch[0], i, particles);}
-            else if branch[1].top_left.x < p.r.x && p.r.x < branch[1].top_left.x + branch[1].width && branch[1].top_left.y < p.r.y && p.r.y < branch[1].top_left.y + branch[1].height  {put(&mut branch[1], i, particles);}
-            else if branch[2].top_left.x < p.r.x && p.r.x < branch[2].top_left.x + branch[2].width && branch[2].top_left.y < p.r.y && p.r.y < branch[2].top_left.y + branch[2].height  {put(&mut branch[2], i, particles);}
-            else if branch[3].top_left.x < p.r.x && p.r.x < branch[3].top_left.x + branch[3].width && branch[3].top_left.y < p.r.y && p.r.y < branch[3].top_left.y + branch[3].height  {put(&mut branch[3], i, particles);}
-            else {}
+        // Branch nodes accumulate mass/center_of_mass_sum too, so every node's mass always
+        // reflects the total of its whole subtree, not just the pair that caused the split.
+        // This matters beyond the density export: `calculate_field`'s Barnes-Hut
+        // approximation (the `s/d <= theta` branch) reads `node.mass`/`center_of_mass_sum`
+        // directly, so before this fix every internal node's mass was frozen at its first two
+        // particles and the simulation's gravity was wrong for any node theta let it
+        // approximate, not just the offline heatmap.
+        QuadTree::Branch(base) => {
+            arena[idx].center_of_mass_sum += p.mass*p.r;
+            arena[idx].mass += p.mass;
+            if let Some(c) = route_to_child(arena, base as usize, p.r) {
+                put(arena, c, i, particles);
+            }
         }
     };
 }
 
-fn calculate_field(node: &QuadNode, i: usize, particles: &mut Vec<Particle>){
-    match &node.qt{
+// AABB range query: descend only into nodes whose box overlaps `rect` (given as
+// (top_left, bottom_right)), pushing the particle index of each occupied leaf found.
+// Like the classic retrieve() pattern, this returns candidates bounded by node granularity,
+// not an exact point-in-rect test against each particle.
+fn query(arena: &[QuadNode], idx: usize, rect: (Vec2, Vec2), out: &mut Vec<usize>) {
+    let node = &arena[idx];
+    let (rmin, rmax) = rect;
+    if node.top_left.x > rmax.x || node.top_left.x + node.width < rmin.x
+        || node.top_left.y > rmax.y || node.top_left.y + node.height < rmin.y {
+        return;
+    }
+    match node.qt {
+        QuadTree::Leaf(None) => {},
+        QuadTree::Leaf(Some(j)) => { out.push(j); },
+        QuadTree::Branch(base) => {
+            let base = base as usize;
+            query(arena, base,   rect, out);
+            query(arena, base+1, rect, out);
+            query(arena, base+2, rect, out);
+            query(arena, base+3, rect, out);
+        },
+    }
+}
+
+// Overwrite the pixel at (x, y) with `rgba`, no-op if outside the frame buffer
+fn put_pixel(frame: &mut [u8], x: i32, y: i32, rgba: [u8; 4]) {
+    if x < 0 || y < 0 || x >= WIDTH as i32 || y >= HEIGHT as i32 {
+        return;
+    }
+    let idx = 4 * (WIDTH as i32 * y + x) as usize;
+    frame[idx..idx + 4].copy_from_slice(&rgba);
+}
+
+// Draw the outline of a node's rectangle, walking all four edges
+fn draw_rect_outline(frame: &mut [u8], top_left: Vec2, width: f32, height: f32, rgba: [u8; 4]) {
+    let x0 = top_left.x as i32;
+    let y0 = top_left.y as i32;
+    let x1 = (top_left.x + width) as i32;
+    let y1 = (top_left.y + height) as i32;
+    for x in x0..=x1 {
+        put_pixel(frame, x, y0, rgba);
+        put_pixel(frame, x, y1, rgba);
+    }
+    for y in y0..=y1 {
+        put_pixel(frame, x0, y, rgba);
+        put_pixel(frame, x1, y, rgba);
+    }
+}
+
+// Walk the tree drawing each node's boundary; each occupied leaf additionally gets one pixel
+// just inside its top-left corner, shaded by mass/area so denser leaves glow brighter
+fn draw_quadtree_overlay(frame: &mut [u8], arena: &[QuadNode], idx: usize) {
+    let node = &arena[idx];
+    draw_rect_outline(frame, node.top_left, node.width, node.height, [0, 80, 0, 0xff]);
+    match node.qt {
+        QuadTree::Leaf(Some(_)) => {
+            let density = node.mass / (node.width * node.height);
+            let glow = (density.min(1.) * 255.) as u8;
+            put_pixel(frame, node.top_left.x as i32 + 1, node.top_left.y as i32 + 1, [0, glow, 0, 0xff]);
+        },
+        QuadTree::Leaf(None) => {},
+        QuadTree::Branch(base) => {
+            let base = base as usize;
+            draw_quadtree_overlay(frame, arena, base);
+            draw_quadtree_overlay(frame, arena, base+1);
+            draw_quadtree_overlay(frame, arena, base+2);
+            draw_quadtree_overlay(frame, arena, base+3);
+        },
+    }
+}
+
+// Flood an image rectangle with a single flat color, clamped to the image bounds
+fn fill_rect(img: &mut RgbaImage, top_left: Vec2, width: f32, height: f32, rgba: Rgba<u8>) {
+    let x0 = top_left.x.max(0.) as u32;
+    let y0 = top_left.y.max(0.) as u32;
+    let x1 = (top_left.x + width).min(WIDTH as f32) as u32;
+    let y1 = (top_left.y + height).min(HEIGHT as f32) as u32;
+    for y in y0..y1 {
+        for x in x0..x1 {
+            img.put_pixel(x, y, rgba);
+        }
+    }
+}
+
+// Recursively paint a variance-adaptive density heatmap: nodes whose mass is below
+// `threshold` are painted as one flat block shaded by mass/area; only nodes above the
+// threshold are subdivided further, so detail only appears where the tree already split
+fn render_density_image(arena: &[QuadNode], idx: usize, img: &mut RgbaImage, threshold: f32) {
+    let node = &arena[idx];
+    let flat_color = |node: &QuadNode| {
+        let density = node.mass / (node.width * node.height);
+        Rgba([0, (density.min(1.) * 255.) as u8, 0, 0xff])
+    };
+    match node.qt {
+        QuadTree::Leaf(_) => fill_rect(img, node.top_left, node.width, node.height, flat_color(node)),
+        QuadTree::Branch(base) => {
+            if node.mass < threshold {
+                fill_rect(img, node.top_left, node.width, node.height, flat_color(node));
+            } else {
+                let base = base as usize;
+                render_density_image(arena, base,   img, threshold);
+                render_density_image(arena, base+1, img, threshold);
+                render_density_image(arena, base+2, img, threshold);
+                render_density_image(arena, base+3, img, threshold);
+            }
+        },
+    }
+}
+
+fn calculate_field(arena: &[QuadNode], idx: usize, i: usize, particles: &mut [Particle], theta: f32, softening: f32, grav: f32){
+    let node = &arena[idx];
+    match node.qt{
         // empty nodes make no field
         QuadTree::Leaf(None) => {},
         // for a node with single particle, ie leaf, calculate the distance separate
-        &QuadTree::Leaf(Some(j)) => {
+        QuadTree::Leaf(Some(j)) => {
             if i!=j{
                 let r:Vec2 = particles[j].r - particles[i].r;
-                let d = (r.length_squared()+SOFTENING*SOFTENING).sqrt();
+                let d = (r.length_squared()+softening*softening).sqrt();
                 let m = particles[j].mass;
-                let field = GRAV*m*r/d/d/d;
+                let field = grav*m*r/d/d/d;
                 particles[i].field += field;
             }
         },
         // if its a branch, either check if the condition for approximation holds true, or recursve
         // through the tree until all particles are included
-        QuadTree::Branch(branch) => {
+        QuadTree::Branch(base) => {
             let m = node.mass;
             let r:Vec2 = node.center_of_mass_sum/m - particles[i].r;
-            let d = (r.length_squared() + SOFTENING*SOFTENING).sqrt();
+            let d = (r.length_squared() + softening*softening).sqrt();
             let s = node.width;
-            if s/d <= THETA {
-                particles[i].field += GRAV*m*r/d/d/d;
+            if s/d <= theta {
+                particles[i].field += grav*m*r/d/d/d;
             }
             else {
-                calculate_field(&branch[0], i, particles);
-                calculate_field(&branch[1], i, particles);
-                calculate_field(&branch[2], i, particles);
-                calculate_field(&branch[3], i, particles);
+                let base = base as usize;
+                calculate_field(arena, base,   i, particles, theta, softening, grav);
+                calculate_field(arena, base+1, i, particles, theta, softening, grav);
+                calculate_field(arena, base+2, i, particles, theta, softening, grav);
+                calculate_field(arena, base+3, i, particles, theta, softening, grav);
             }
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_tree(particles: &[Particle]) -> Vec<QuadNode> {
+        let mut arena = vec![QuadNode {
+            top_left: Vec2::ZERO,
+            width: WIDTH as f32,
+            height: HEIGHT as f32,
+            center_of_mass_sum: Vec2::ZERO,
+            mass: 0.,
+            qt: QuadTree::Leaf(None),
+        }];
+        for i in 0..particles.len() {
+            put(&mut arena, ROOT, i, particles);
+        }
+        arena
+    }
+
+    #[test]
+    fn particle_on_a_split_line_is_not_dropped_from_the_tree() {
+        // Regression test: a particle sitting exactly on the root's split lines (e.g. the
+        // domain center) must still be reachable via query, not silently excluded.
+        let particles = vec![
+            Particle { mass: 1., r: Vec2 { x: (WIDTH / 2) as f32, y: (HEIGHT / 2) as f32 }, ..Default::default() },
+            Particle { mass: 1., r: Vec2 { x: 10., y: 10. }, ..Default::default() },
+        ];
+        let arena = build_tree(&particles);
+        let mut found = Vec::new();
+        query(&arena, ROOT, (Vec2::ZERO, Vec2::new(WIDTH as f32, HEIGHT as f32)), &mut found);
+        found.sort();
+        assert_eq!(found, vec![0, 1]);
+    }
+
+    #[test]
+    fn branch_mass_equals_sum_of_its_whole_subtree() {
+        // Pins the invariant calculate_field's Barnes-Hut approximation relies on: a Branch
+        // node's mass must be the total of every particle beneath it, not just the pair that
+        // triggered its split.
+        let particles = vec![
+            Particle { mass: 2., r: Vec2 { x: 10., y: 10. }, ..Default::default() },
+            Particle { mass: 3., r: Vec2 { x: 20., y: 20. }, ..Default::default() },
+            Particle { mass: 5., r: Vec2 { x: 15., y: 15. }, ..Default::default() },
+            Particle { mass: 7., r: Vec2 { x: 400., y: 450. }, ..Default::default() },
+        ];
+        let arena = build_tree(&particles);
+        let expected: f32 = particles.iter().map(|p| p.mass).sum();
+        assert!((arena[ROOT].mass - expected).abs() < 1e-4);
+        // the densely-populated top-left quadrant (3 of the 4 particles) must also carry
+        // their combined mass, not just the first two that forced it to split
+        if let QuadTree::Branch(base) = arena[ROOT].qt {
+            let quadrant = route_to_child(&arena, base as usize, Vec2 { x: 10., y: 10. }).unwrap();
+            assert!((arena[quadrant].mass - 10.).abs() < 1e-4);
+        } else {
+            panic!("expected the root to have split into a branch");
+        }
+    }
+
+    #[test]
+    fn wrap_periodic_stays_inside_the_domain() {
+        let wrapped = wrap_periodic(Vec2 { x: -5., y: HEIGHT as f32 + 5. });
+        assert!(wrapped.x >= 0. && wrapped.x < WIDTH as f32);
+        assert!(wrapped.y >= 0. && wrapped.y < HEIGHT as f32);
+        // a point exactly on the lower edge must wrap to itself, not escape the domain
+        assert_eq!(wrap_periodic(Vec2::ZERO), Vec2::ZERO);
+    }
+
+    #[test]
+    fn query_only_returns_particles_whose_leaf_overlaps_the_rect() {
+        let particles = vec![
+            Particle { mass: 1., r: Vec2 { x: 50., y: 50. }, ..Default::default() },
+            Particle { mass: 1., r: Vec2 { x: 400., y: 400. }, ..Default::default() },
+        ];
+        let arena = build_tree(&particles);
+        let mut found = Vec::new();
+        query(&arena, ROOT, (Vec2::new(0., 0.), Vec2::new(100., 100.)), &mut found);
+        assert_eq!(found, vec![0]);
+    }
+
+    #[test]
+    fn query_rect_entirely_outside_the_domain_finds_nothing() {
+        let particles = vec![Particle { mass: 1., r: Vec2 { x: 50., y: 50. }, ..Default::default() }];
+        let arena = build_tree(&particles);
+        let mut found = Vec::new();
+        query(&arena, ROOT, (Vec2::new(1000., 1000.), Vec2::new(1010., 1010.)), &mut found);
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn lattice_bcc_fills_the_domain_without_exceeding_its_bounds() {
+        let particles = lattice_bcc();
+        assert!(!particles.is_empty());
+        for p in &particles {
+            assert!(p.r.x >= 0. && p.r.x < WIDTH as f32);
+            assert!(p.r.y >= 0. && p.r.y < HEIGHT as f32);
+            assert_eq!(p.mass, 1.);
+            assert_eq!(p.v, Vec2::ZERO);
+        }
+    }
+
+    #[test]
+    fn rotating_disk_seeds_n_orbiters_plus_one_central_mass() {
+        let particles = rotating_disk();
+        assert_eq!(particles.len(), N + 1);
+        assert_eq!(particles[0].mass, M);
+        assert_eq!(particles[0].r, Vec2 { x: (WIDTH / 2) as f32, y: (HEIGHT / 2) as f32 });
+        // an orbiter's velocity should be roughly perpendicular to its radius from the center
+        let orbiter = particles[1];
+        let radial = orbiter.r - particles[0].r;
+        assert!(radial.dot(orbiter.v).abs() < 1e-2 * radial.length() * orbiter.v.length());
+    }
+
+    #[test]
+    fn cold_cloud_seeds_n_motionless_particles() {
+        let particles = cold_cloud();
+        assert_eq!(particles.len(), N);
+        assert!(particles.iter().all(|p| p.v == Vec2::ZERO && p.mass == 1.));
+    }
+
+    #[test]
+    fn colliding_disks_orbiters_add_tangential_velocity_to_their_disks_bulk_velocity() {
+        let particles = colliding_disks();
+        let bulk_v = particles[0].v;
+        let orbiter = particles[1];
+        assert!((orbiter.v - bulk_v).length() > 0.1);
+        // the part of the orbiter's velocity beyond the bulk velocity is tangential to its disk
+        let radial = orbiter.r - particles[0].r;
+        let tangential = orbiter.v - bulk_v;
+        assert!(radial.dot(tangential).abs() < 1e-2 * radial.length() * tangential.length());
+    }
+}